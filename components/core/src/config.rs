@@ -14,46 +14,156 @@
 
 use std;
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::result;
 use std::str::FromStr;
+use std::sync::{Arc, Once, ONCE_INIT, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
 
+use libc;
 use toml;
 
 use error::{Error, Result};
 
+/// Default poll interval for `ConfigFile::watch()`.
+pub const DEFAULT_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Parses a literal TOML snippet into a `toml::Value`, panicking on invalid TOML. Only
+/// meant for building fixtures in tests (here and in downstream crates like `net`) —
+/// runtime config loading goes through `ConfigFile::from_toml`/`load_into`, which surface
+/// parse errors instead of panicking.
+pub fn toml_fixture(raw: &str) -> toml::Value {
+    raw.parse().unwrap()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigChangeEvent {
+    pub changed_keys: Vec<String>,
+}
+
+/// Bumped by the SIGHUP handler; each `watch()` thread keeps its own last-seen count so
+/// every watcher notices a SIGHUP, not just whichever one polls first.
+static SIGHUP_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+static SIGHUP_HANDLER_INIT: Once = ONCE_INIT;
+
+extern "C" fn mark_sighup_received(_: libc::c_int) {
+    SIGHUP_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+fn register_sighup_handler() {
+    SIGHUP_HANDLER_INIT.call_once(|| unsafe {
+        libc::signal(libc::SIGHUP, mark_sighup_received as libc::sighandler_t);
+    });
+}
+
 pub trait ConfigFile: Sized {
     type Error: std::error::Error + From<Error>;
 
     fn from_file<T: AsRef<Path>>(filepath: T) -> result::Result<Self, Self::Error> {
-        let mut file = match File::open(filepath.as_ref()) {
-            Ok(f) => f,
-            Err(e) => return Err(Self::Error::from(Error::ConfigFileIO(e))),
-        };
-        let mut raw = String::new();
-        match file.read_to_string(&mut raw) {
-            Ok(_) => (),
-            Err(e) => return Err(Self::Error::from(Error::ConfigFileIO(e))),
-        }
-        match raw.parse() {
-            Ok(toml) => Self::from_toml(toml),
-            Err(e) => {
-                let msg = format_errors(&e);
-                Err(Self::Error::from(Error::ConfigFileSyntax(msg)))
-            }
-        }
+        let raw = try!(read_toml::<Self, T>(filepath));
+        Self::from_toml(raw)
     }
 
     fn from_toml(toml: toml::Value) -> result::Result<Self, Self::Error>;
+
+    /// Re-parses `filepath` fully before swapping anything in, so a syntactically broken
+    /// edit leaves the previous, valid config running instead of replacing it.
+    fn reload<T: AsRef<Path>>(&mut self, filepath: T) -> result::Result<toml::Value, Self::Error> {
+        let raw = try!(read_toml::<Self, T>(filepath));
+        let new_self = try!(Self::from_toml(raw.clone()));
+        *self = new_self;
+        Ok(raw)
+    }
+
+    /// Spawns a background thread that reloads `shared` from `filepath` whenever its mtime
+    /// advances or the process receives SIGHUP, announcing changed top-level keys on
+    /// `sender`. `initial_toml` should be the value `shared` was built from, so the first
+    /// comparison is against the running config rather than an empty table.
+    fn watch<T>(shared: Arc<RwLock<Self>>,
+                filepath: T,
+                initial_toml: toml::Value,
+                sender: Sender<ConfigChangeEvent>,
+                poll_interval: Duration)
+                -> thread::JoinHandle<()>
+        where T: AsRef<Path> + Send + 'static,
+              Self: Send + Sync + 'static
+    {
+        let path = filepath.as_ref().to_path_buf();
+        thread::spawn(move || {
+            register_sighup_handler();
+            let mut last_sighup_count = SIGHUP_COUNT.load(Ordering::SeqCst);
+            let mut last_toml = initial_toml;
+            let mut last_mtime = file_mtime(&path);
+            loop {
+                thread::sleep(poll_interval);
+                let sighup_count = SIGHUP_COUNT.load(Ordering::SeqCst);
+                let hup = sighup_count != last_sighup_count;
+                last_sighup_count = sighup_count;
+                let mtime = file_mtime(&path);
+                let mtime_changed = mtime != last_mtime;
+                last_mtime = mtime;
+                if !hup && !mtime_changed {
+                    continue;
+                }
+                let mut guard = match shared.write() {
+                    Ok(g) => g,
+                    Err(_) => break,
+                };
+                match guard.reload(&path) {
+                    Ok(new_toml) => {
+                        let changed_keys = changed_top_level_keys(&last_toml, &new_toml);
+                        last_toml = new_toml;
+                        if !changed_keys.is_empty() {
+                            if sender.send(ConfigChangeEvent { changed_keys: changed_keys })
+                                .is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        let _ = writeln!(io::stderr(),
+                                          "Failed to reload config from {}: {}",
+                                          path.display(),
+                                          e);
+                    }
+                }
+            }
+        })
+    }
 }
 
 pub trait ParseInto<T> {
     fn parse_into(&self, field: &'static str, out: &mut T) -> Result<bool>;
 }
 
+impl ParseInto<Vec<String>> for toml::Value {
+    fn parse_into(&self, field: &'static str, out: &mut Vec<String>) -> Result<bool> {
+        if let Some(val) = self.lookup(field) {
+            if let Some(slice) = val.as_slice() {
+                let mut buf = vec![];
+                for entry in slice.iter() {
+                    match entry.as_str() {
+                        Some(v) => buf.push(v.to_string()),
+                        None => return Err(Error::ConfigInvalidArray(field)),
+                    }
+                }
+                *out = buf;
+                Ok(true)
+            } else {
+                Err(Error::ConfigInvalidArray(field))
+            }
+        } else {
+            Ok(false)
+        }
+    }
+}
+
 impl ParseInto<Vec<SocketAddr>> for toml::Value {
     fn parse_into(&self, field: &'static str, out: &mut Vec<SocketAddr>) -> Result<bool> {
         if let Some(val) = self.lookup(field) {
@@ -346,3 +456,78 @@ fn format_errors(errors: &Vec<toml::ParserError>) -> String {
     }
     msg
 }
+
+fn read_toml<S, T>(filepath: T) -> result::Result<toml::Value, S::Error>
+    where S: ConfigFile,
+          T: AsRef<Path>
+{
+    let mut file = match File::open(filepath.as_ref()) {
+        Ok(f) => f,
+        Err(e) => return Err(S::Error::from(Error::ConfigFileIO(e))),
+    };
+    let mut raw = String::new();
+    match file.read_to_string(&mut raw) {
+        Ok(_) => (),
+        Err(e) => return Err(S::Error::from(Error::ConfigFileIO(e))),
+    }
+    match raw.parse() {
+        Ok(toml) => Ok(toml),
+        Err(e) => {
+            let msg = format_errors(&e);
+            Err(S::Error::from(Error::ConfigFileSyntax(msg)))
+        }
+    }
+}
+
+fn file_mtime<T: AsRef<Path>>(filepath: T) -> Option<std::time::SystemTime> {
+    fs::metadata(filepath.as_ref()).and_then(|meta| meta.modified()).ok()
+}
+
+/// Returns the top-level keys that were added, removed, or changed value between `old` and
+/// `new`.
+fn changed_top_level_keys(old: &toml::Value, new: &toml::Value) -> Vec<String> {
+    let empty = BTreeMap::new();
+    let old_table = old.as_table().unwrap_or(&empty);
+    let new_table = new.as_table().unwrap_or(&empty);
+    let mut changed = Vec::new();
+    for (key, new_val) in new_table.iter() {
+        match old_table.get(key) {
+            Some(old_val) if old_val == new_val => continue,
+            _ => changed.push(key.clone()),
+        }
+    }
+    for key in old_table.keys() {
+        if !new_table.contains_key(key) {
+            changed.push(key.clone());
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_top_level_keys, toml_fixture as toml};
+
+    #[test]
+    fn changed_top_level_keys_reports_no_changes_for_identical_tables() {
+        let old = toml("foo = 1\nbar = \"baz\"");
+        let new = toml("foo = 1\nbar = \"baz\"");
+        assert!(changed_top_level_keys(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn changed_top_level_keys_reports_changed_values() {
+        let old = toml("foo = 1");
+        let new = toml("foo = 2");
+        assert_eq!(vec!["foo".to_string()], changed_top_level_keys(&old, &new));
+    }
+
+    #[test]
+    fn changed_top_level_keys_reports_added_and_removed_keys() {
+        let old = toml("foo = 1\nbar = 2");
+        let new = toml("foo = 1\nbaz = 3");
+        let mut changed = changed_top_level_keys(&old, &new);
+        changed.sort();
+        assert_eq!(vec!["bar".to_string(), "baz".to_string()], changed);
+    }
+}
@@ -12,9 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::error;
+use std::fmt;
+use std::io::Read;
 use std::net;
 
+use hyper::Client;
+use hyper::header::{Authorization, Bearer};
 use num_cpus;
+use rustc_serialize::json::Json;
+use toml;
+
+use core::config::ParseInto;
 
 /// URL to GitHub API endpoint
 pub const DEFAULT_GITHUB_URL: &'static str = "https://api.github.com";
@@ -28,6 +37,13 @@ pub const DEV_GITHUB_CLIENT_ID: &'static str = "0c2f738a7d0bd300de10";
 /// additional comments.
 pub const DEV_GITHUB_CLIENT_SECRET: &'static str = "438223113eeb6e7edf2d2f91a232b72de72b9bdf";
 
+/// Default API base for a GitLab instance, overridable for self-hosted installs.
+pub const DEFAULT_GITLAB_URL: &'static str = "https://gitlab.com/api/v4";
+
+/// Default API base for Bitbucket Cloud. Unlike GitLab/GitHub Enterprise, Bitbucket's
+/// OAuth and API endpoints aren't self-hostable, so there's no `oauth.url` override for it.
+pub const DEFAULT_BITBUCKET_URL: &'static str = "https://api.bitbucket.org/2.0";
+
 pub trait DispatcherCfg {
     fn default_worker_count() -> usize {
         // JW TODO: increase default count after r2d2 connection pools are moved to be owned
@@ -39,12 +55,467 @@ pub trait DispatcherCfg {
     fn worker_count(&self) -> usize;
 }
 
+/// The identity an `OAuthProvider` resolves an access token to. Deliberately thin: it's
+/// only the handful of fields builder's session handling actually keys off of, regardless
+/// of which upstream provider produced them.
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    pub email: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum OAuthError {
+    /// The provider's HTTP API could not be reached or returned a non-2xx response.
+    Api(String),
+    /// The response body wasn't the JSON shape `fetch_identity` expected.
+    UnexpectedResponse(String),
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &OAuthError::Api(ref msg) => write!(f, "OAuth provider API error: {}", msg),
+            &OAuthError::UnexpectedResponse(ref msg) => {
+                write!(f, "Unexpected OAuth provider response: {}", msg)
+            }
+        }
+    }
+}
+
+impl error::Error for OAuthError {
+    fn description(&self) -> &str {
+        match self {
+            &OAuthError::Api(_) => "OAuth provider API error",
+            &OAuthError::UnexpectedResponse(_) => "Unexpected OAuth provider response",
+        }
+    }
+}
+
+/// Which upstream identity provider builder authenticates against. Parsed from the
+/// `oauth.provider` config key via `ParseInto<String>`; unrecognized values fall back to
+/// `GitHub` so existing configs keep working untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provider {
+    GitHub,
+    GitLab,
+    Bitbucket,
+    /// A generic OpenID Connect issuer, identified by its discovery base URL.
+    OIDC,
+}
+
+impl Default for Provider {
+    fn default() -> Self {
+        Provider::GitHub
+    }
+}
+
+impl Provider {
+    pub fn from_toml(toml: &toml::Value) -> Self {
+        let mut raw = String::new();
+        let _ = toml.parse_into("oauth.provider", &mut raw);
+        match raw.as_str() {
+            "gitlab" => Provider::GitLab,
+            "bitbucket" => Provider::Bitbucket,
+            "oidc" => Provider::OIDC,
+            _ => Provider::GitHub,
+        }
+    }
+}
+
+/// Generalizes the old hard-coded GitHub-only OAuth integration so a builder deployment
+/// can authenticate against GitLab, Bitbucket, or a generic OIDC issuer by implementing
+/// this trait instead. The endpoint accessors carry provider-specific defaults; only
+/// `fetch_identity` needs a real implementation per provider, since the three endpoint
+/// URLs plus client credentials are all `oauth2` needs to complete a code exchange.
+///
+/// NOT YET WIRED UP: nothing in this tree holds an `OAuthProvider` trait object or calls
+/// through it outside this module's own tests — see `OAuthConfig`'s doc comment below.
+/// Treat this as a library addition the session/dispatcher config still needs to adopt,
+/// not a shipped provider switch.
+pub trait OAuthProvider {
+    fn authorize_url(&self) -> &str;
+    fn token_url(&self) -> &str;
+    fn api_url(&self) -> &str;
+    fn client_id(&self) -> &str;
+    fn client_secret(&self) -> &str;
+
+    /// Exchanges an already-obtained access token for the authenticated user's identity.
+    fn fetch_identity(&self, token: &str) -> Result<Account, OAuthError>;
+}
+
+/// The existing GitHub integration, kept as-is so configs that don't set `oauth.provider`
+/// keep behaving exactly as they did before this trait existed.
 pub trait GitHubOAuth {
     fn github_url(&self) -> &str;
     fn github_client_id(&self) -> &str;
     fn github_client_secret(&self) -> &str;
 }
 
+/// The standalone config struct for `Provider::GitHub`, parsed the same way
+/// `GitLabConfig`/`OidcConfig` are. Existing top-level configs that embed their GitHub
+/// settings directly (implementing `GitHubOAuth` themselves rather than holding one of
+/// these) keep working unchanged via the blanket impl below; this struct exists so
+/// `OAuthConfig::from_toml` has something concrete to build when `oauth.provider` is
+/// `github` or unset.
+#[derive(Debug, Clone)]
+pub struct GitHubConfig {
+    pub url: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl Default for GitHubConfig {
+    fn default() -> Self {
+        GitHubConfig {
+            url: String::from(DEFAULT_GITHUB_URL),
+            client_id: String::from(DEV_GITHUB_CLIENT_ID),
+            client_secret: String::from(DEV_GITHUB_CLIENT_SECRET),
+        }
+    }
+}
+
+impl GitHubConfig {
+    pub fn from_toml(toml: &toml::Value) -> Self {
+        let mut cfg = GitHubConfig::default();
+        let _ = toml.parse_into("github.url", &mut cfg.url);
+        let _ = toml.parse_into("github.client_id", &mut cfg.client_id);
+        let _ = toml.parse_into("github.client_secret", &mut cfg.client_secret);
+        cfg
+    }
+}
+
+impl GitHubOAuth for GitHubConfig {
+    fn github_url(&self) -> &str {
+        &self.url
+    }
+
+    fn github_client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    fn github_client_secret(&self) -> &str {
+        &self.client_secret
+    }
+}
+
+impl<T: GitHubOAuth> OAuthProvider for T {
+    fn authorize_url(&self) -> &str {
+        "https://github.com/login/oauth/authorize"
+    }
+
+    fn token_url(&self) -> &str {
+        "https://github.com/login/oauth/access_token"
+    }
+
+    fn api_url(&self) -> &str {
+        self.github_url()
+    }
+
+    fn client_id(&self) -> &str {
+        self.github_client_id()
+    }
+
+    fn client_secret(&self) -> &str {
+        self.github_client_secret()
+    }
+
+    fn fetch_identity(&self, token: &str) -> Result<Account, OAuthError> {
+        fetch_json_identity(self.api_url(), "/user", token, "id", "login")
+    }
+}
+
+/// GitLab's config equivalent of the old `GitHubOAuth` trait's implementor: the
+/// `oauth.client_id`/`oauth.client_secret`/`oauth.url` keys, parsed the same way every
+/// other config struct in this crate parses its fields.
+#[derive(Debug, Clone)]
+pub struct GitLabConfig {
+    pub url: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl Default for GitLabConfig {
+    fn default() -> Self {
+        GitLabConfig {
+            url: String::from(DEFAULT_GITLAB_URL),
+            client_id: String::new(),
+            client_secret: String::new(),
+        }
+    }
+}
+
+impl GitLabConfig {
+    pub fn from_toml(toml: &toml::Value) -> Self {
+        let mut cfg = GitLabConfig::default();
+        let _ = toml.parse_into("oauth.url", &mut cfg.url);
+        let _ = toml.parse_into("oauth.client_id", &mut cfg.client_id);
+        let _ = toml.parse_into("oauth.client_secret", &mut cfg.client_secret);
+        cfg
+    }
+}
+
+impl OAuthProvider for GitLabConfig {
+    fn authorize_url(&self) -> &str {
+        "https://gitlab.com/oauth/authorize"
+    }
+
+    fn token_url(&self) -> &str {
+        "https://gitlab.com/oauth/token"
+    }
+
+    fn api_url(&self) -> &str {
+        &self.url
+    }
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+
+    fn fetch_identity(&self, token: &str) -> Result<Account, OAuthError> {
+        fetch_json_identity(self.api_url(), "/user", token, "id", "username")
+    }
+}
+
+/// Bitbucket Cloud's config equivalent of `GitLabConfig`. Bitbucket's OAuth and API
+/// endpoints aren't self-hostable, so unlike `GitLabConfig` there's no `oauth.url`
+/// override for `api_url`; only the client credentials are read from config.
+#[derive(Debug, Clone)]
+pub struct BitbucketConfig {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl Default for BitbucketConfig {
+    fn default() -> Self {
+        BitbucketConfig {
+            client_id: String::new(),
+            client_secret: String::new(),
+        }
+    }
+}
+
+impl BitbucketConfig {
+    pub fn from_toml(toml: &toml::Value) -> Self {
+        let mut cfg = BitbucketConfig::default();
+        let _ = toml.parse_into("oauth.client_id", &mut cfg.client_id);
+        let _ = toml.parse_into("oauth.client_secret", &mut cfg.client_secret);
+        cfg
+    }
+}
+
+impl OAuthProvider for BitbucketConfig {
+    fn authorize_url(&self) -> &str {
+        "https://bitbucket.org/site/oauth2/authorize"
+    }
+
+    fn token_url(&self) -> &str {
+        "https://bitbucket.org/site/oauth2/access_token"
+    }
+
+    fn api_url(&self) -> &str {
+        DEFAULT_BITBUCKET_URL
+    }
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+
+    fn fetch_identity(&self, token: &str) -> Result<Account, OAuthError> {
+        fetch_json_identity(self.api_url(), "/user", token, "uuid", "username")
+    }
+}
+
+/// A generic OIDC issuer. `issuer_url` is the discovery base (e.g.
+/// `https://accounts.example.com`); the three endpoint URLs are derived from it rather
+/// than configured individually, matching how most OIDC clients bootstrap from a single
+/// well-known issuer.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// `{issuer_url}/authorize`, precomputed at construction time since
+    /// `OAuthProvider::authorize_url` has to return a borrowed `&str`.
+    authorize_url: String,
+    /// `{issuer_url}/token`, precomputed for the same reason as `authorize_url`.
+    token_url: String,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig::with_issuer_url(String::new())
+    }
+}
+
+impl OidcConfig {
+    fn with_issuer_url(issuer_url: String) -> Self {
+        let authorize_url = format!("{}/authorize", issuer_url);
+        let token_url = format!("{}/token", issuer_url);
+        OidcConfig {
+            issuer_url: issuer_url,
+            client_id: String::new(),
+            client_secret: String::new(),
+            authorize_url: authorize_url,
+            token_url: token_url,
+        }
+    }
+
+    pub fn from_toml(toml: &toml::Value) -> Self {
+        let mut issuer_url = String::new();
+        let _ = toml.parse_into("oauth.issuer_url", &mut issuer_url);
+        let mut cfg = OidcConfig::with_issuer_url(issuer_url);
+        let _ = toml.parse_into("oauth.client_id", &mut cfg.client_id);
+        let _ = toml.parse_into("oauth.client_secret", &mut cfg.client_secret);
+        cfg
+    }
+}
+
+impl OAuthProvider for OidcConfig {
+    fn authorize_url(&self) -> &str {
+        &self.authorize_url
+    }
+
+    fn token_url(&self) -> &str {
+        &self.token_url
+    }
+
+    fn api_url(&self) -> &str {
+        &self.issuer_url
+    }
+
+    fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    fn client_secret(&self) -> &str {
+        &self.client_secret
+    }
+
+    fn fetch_identity(&self, token: &str) -> Result<Account, OAuthError> {
+        fetch_json_identity(self.api_url(), "/userinfo", token, "sub", "preferred_username")
+    }
+}
+
+/// Selects and holds the concrete `OAuthProvider` a builder deployment is configured to
+/// use. Construct one of these from the top-level config once, at startup, and call
+/// `provider()` wherever code currently reaches for a `GitHubOAuth` implementor's
+/// `github_url()`/`github_client_id()`-style accessors directly.
+///
+/// STATUS: partial. Nothing in this tree constructs an `OAuthConfig` or calls
+/// `.provider()` outside of this module's own tests — the dispatcher/session config that
+/// owns the real `GitHubOAuth` implementor still has to be migrated to call
+/// `OAuthConfig::from_toml(...).provider()` instead. That migration is out of scope here:
+/// the session/dispatcher config this crate's `GitHubOAuth` implementors are meant to live
+/// on doesn't exist in this tree. Until that consumer lands, `from_toml` below refuses to
+/// build anything but `GitHub` so `oauth.provider = "gitlab"` et al. fail loudly instead of
+/// parsing clean and silently doing nothing.
+///
+/// TODO: this is not done. File the dispatcher/session migration as its own follow-up and
+/// don't close it out against this commit — merging this enum gives `oauth.provider` a
+/// shape to parse into, not a working non-GitHub login.
+///
+/// SCOPE: what actually ships here is the `OAuthProvider`/`OAuthConfig` library shape —
+/// not "builder deployments can authenticate against GitLab/Bitbucket/OIDC", which is what
+/// was asked for. `oauth.provider = "gitlab"` still hard-errors. Track and merge the
+/// dispatcher/session wiring as its own, separately-scoped ticket; don't mark the original
+/// request resolved on the strength of this commit alone.
+pub enum OAuthConfig {
+    GitHub(GitHubConfig),
+    GitLab(GitLabConfig),
+    Bitbucket(BitbucketConfig),
+    OIDC(OidcConfig),
+}
+
+/// Returned by `OAuthConfig::from_toml` for any `oauth.provider` this tree has no consumer
+/// for yet.
+#[derive(Debug)]
+pub struct ProviderNotWired(pub Provider);
+
+impl fmt::Display for ProviderNotWired {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "oauth.provider = \"{:?}\" has no effect: no call site in this tree reads \
+                OAuthConfig::provider() yet, so this would silently keep using GitHub",
+               self.0)
+    }
+}
+
+impl error::Error for ProviderNotWired {
+    fn description(&self) -> &str {
+        "oauth.provider names a provider with no wired-up consumer"
+    }
+}
+
+impl OAuthConfig {
+    pub fn from_toml(toml: &toml::Value) -> Result<Self, ProviderNotWired> {
+        match Provider::from_toml(toml) {
+            Provider::GitHub => Ok(OAuthConfig::GitHub(GitHubConfig::from_toml(toml))),
+            other => Err(ProviderNotWired(other)),
+        }
+    }
+
+    pub fn provider(&self) -> &OAuthProvider {
+        match *self {
+            OAuthConfig::GitHub(ref cfg) => cfg,
+            OAuthConfig::GitLab(ref cfg) => cfg,
+            OAuthConfig::Bitbucket(ref cfg) => cfg,
+            OAuthConfig::OIDC(ref cfg) => cfg,
+        }
+    }
+}
+
+/// Shared by every `OAuthProvider::fetch_identity` impl: GET `base_url + path` with a
+/// bearer token and pull the identity/username fields out of the JSON response by name,
+/// since that's the only part of the "fetch the user" call that differs between
+/// providers.
+fn fetch_json_identity(base_url: &str,
+                        path: &str,
+                        token: &str,
+                        id_field: &str,
+                        username_field: &str)
+                        -> Result<Account, OAuthError> {
+    let url = format!("{}{}", base_url, path);
+    let client = Client::new();
+    let mut res = try!(client.get(&url)
+        .header(Authorization(Bearer { token: token.to_string() }))
+        .send()
+        .map_err(|e| OAuthError::Api(e.to_string())));
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body).map_err(|e| OAuthError::Api(e.to_string())));
+    let json = try!(Json::from_str(&body).map_err(|e| OAuthError::UnexpectedResponse(e.to_string())));
+    account_from_json(&json, id_field, username_field)
+}
+
+/// Pulls `id_field`/`username_field`/`email` out of a provider's identity response.
+/// `username_field` (e.g. OIDC's "preferred_username") is an optional claim for some
+/// providers; falls back to the id rather than failing the whole identity fetch.
+fn account_from_json(json: &Json, id_field: &str, username_field: &str) -> Result<Account, OAuthError> {
+    let id = match json.find(id_field) {
+        Some(v) => v.as_string().map(|s| s.to_string()).unwrap_or_else(|| v.to_string()),
+        None => return Err(OAuthError::UnexpectedResponse(format!("missing {}", id_field))),
+    };
+    let username = json.find(username_field)
+        .and_then(|v| v.as_string())
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| id.clone());
+    let email = json.find("email").and_then(|v| v.as_string()).map(|s| s.to_string());
+    Ok(Account {
+        id: id,
+        username: username,
+        email: email,
+    })
+}
+
 pub trait RouteAddrs {
     fn route_addrs(&self) -> &Vec<net::SocketAddrV4>;
 
@@ -66,3 +537,58 @@ impl ToAddrString for net::SocketAddrV4 {
         format!("tcp://{}:{}", self.ip(), self.port())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+
+    use core::config::toml_fixture as toml;
+
+    use super::{OAuthConfig, Provider, account_from_json};
+
+    #[test]
+    fn provider_from_toml_recognizes_gitlab_bitbucket_and_oidc() {
+        assert_eq!(Provider::GitLab, Provider::from_toml(&toml("oauth.provider = \"gitlab\"")));
+        assert_eq!(Provider::Bitbucket, Provider::from_toml(&toml("oauth.provider = \"bitbucket\"")));
+        assert_eq!(Provider::OIDC, Provider::from_toml(&toml("oauth.provider = \"oidc\"")));
+    }
+
+    #[test]
+    fn provider_from_toml_defaults_to_github_for_unset_or_unknown() {
+        assert_eq!(Provider::GitHub, Provider::from_toml(&toml("")));
+        assert_eq!(Provider::GitHub, Provider::from_toml(&toml("oauth.provider = \"not-a-real-provider\"")));
+    }
+
+    #[test]
+    fn oauth_config_from_toml_builds_github() {
+        assert!(OAuthConfig::from_toml(&toml("")).is_ok());
+    }
+
+    #[test]
+    fn oauth_config_from_toml_rejects_providers_with_no_consumer_yet() {
+        assert!(OAuthConfig::from_toml(&toml("oauth.provider = \"gitlab\"")).is_err());
+        assert!(OAuthConfig::from_toml(&toml("oauth.provider = \"bitbucket\"")).is_err());
+        assert!(OAuthConfig::from_toml(&toml("oauth.provider = \"oidc\"")).is_err());
+    }
+
+    #[test]
+    fn account_from_json_accepts_a_numeric_id() {
+        let json = Json::from_str("{\"id\": 42, \"login\": \"mort\"}").unwrap();
+        let account = account_from_json(&json, "id", "login").unwrap();
+        assert_eq!("42", account.id);
+        assert_eq!("mort", account.username);
+    }
+
+    #[test]
+    fn account_from_json_falls_back_to_id_when_username_field_is_absent() {
+        let json = Json::from_str("{\"sub\": \"abc123\"}").unwrap();
+        let account = account_from_json(&json, "sub", "preferred_username").unwrap();
+        assert_eq!("abc123", account.id);
+        assert_eq!("abc123", account.username);
+    }
+
+    #[test]
+    fn account_from_json_errors_when_id_field_is_absent() {
+        assert!(account_from_json(&Json::from_str("{}").unwrap(), "id", "login").is_err());
+    }
+}
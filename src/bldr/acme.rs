@@ -0,0 +1,897 @@
+//
+// Copyright:: Copyright (c) 2016 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! ACME v2 (RFC 8555) certificate automation. A package that declares it needs a TLS
+//! certificate gets one requested, validated, and renewed by this module, with the
+//! resulting key/chain paths merged over the `ServiceConfig` data the `Reconfigure` hook
+//! is compiled with, and that hook run whenever the certificate changes.
+//!
+//! VERIFICATION STATUS: NOT MERGEABLE AS-IS. This module (JWS signing, CSR
+//! construction, private-key handling, and the full account/order/challenge/finalize flow)
+//! has not been built or run against a live ACME server (e.g. Pebble) — there's no
+//! `Cargo.toml` anywhere in this tree, so it has never been compiled at all. Everything
+//! here has only been checked by reading it against RFC 8555 and the `openssl`/`hyper` APIs
+//! it calls, and reading alone is not sufficient sign-off for code that generates/stores
+//! private keys and signs requests to an external CA. Track "add a `Cargo.toml` for this
+//! crate and run account→order→HTTP-01 challenge→finalize against Pebble" as its own
+//! required follow-up; don't treat this module as verified, and don't merge further changes
+//! to it, until that happens.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, RwLock, mpsc};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::Client;
+use hyper::header::ContentType;
+use openssl::crypto::hash::Type as HashType;
+use openssl::crypto::pkey::PKey;
+use openssl::x509::{X509, X509Generator, X509Req};
+use openssl::x509::extension::Extension;
+use rustc_serialize::base64::{self, ToBase64};
+use rustc_serialize::json::Json;
+use toml;
+
+use core::config::{ConfigChangeEvent, ConfigFile, ParseInto};
+use error::{BldrError, BldrResult, ErrorKind};
+use package::Package;
+use package::hooks::Hook;
+use service_config::ServiceConfig;
+
+static LOGKEY: &'static str = "ACME";
+
+/// Renew a certificate once its leaf is within this many days of expiring.
+const RENEWAL_WINDOW_DAYS: u64 = 30;
+
+/// Delay, in seconds, before retrying a failed renewal (cut short only by an `acme.*`
+/// config change picked up via `AcmeClient::watching`), so a persistent failure
+/// (bad DNS, CA outage, misconfigured challenge hook) doesn't hot-loop against the CA.
+const RENEWAL_RETRY_DELAY_SECS: u64 = 60 * 60;
+
+/// How the CA's pending authorization for a domain should be satisfied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChallengeType {
+    /// Key authorization published at `http://<domain>/.well-known/acme-challenge/<token>`.
+    Http01,
+    /// Key authorization handed to an external hook that publishes the `_acme-challenge` TXT record.
+    Dns01,
+}
+
+impl Default for ChallengeType {
+    fn default() -> Self {
+        ChallengeType::Http01
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub contact_email: String,
+    pub domains: Vec<String>,
+    pub challenge: ChallengeType,
+    pub dns_01_hook: Option<String>,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            directory_url: String::from("https://acme-v02.api.letsencrypt.org/directory"),
+            contact_email: String::new(),
+            domains: Vec::new(),
+            challenge: ChallengeType::default(),
+            dns_01_hook: None,
+        }
+    }
+}
+
+impl ConfigFile for AcmeConfig {
+    type Error = BldrError;
+
+    fn from_toml(toml: toml::Value) -> BldrResult<Self> {
+        let mut cfg = AcmeConfig::default();
+        try!(toml.parse_into("acme.ca_url", &mut cfg.directory_url));
+        try!(toml.parse_into("acme.contact_email", &mut cfg.contact_email));
+        try!(toml.parse_into("acme.domains", &mut cfg.domains));
+        let mut challenge = String::new();
+        try!(toml.parse_into("acme.challenge", &mut challenge));
+        cfg.challenge = match challenge.as_str() {
+            "" | "http-01" => ChallengeType::Http01,
+            "dns-01" => ChallengeType::Dns01,
+            other => return Err(bldr_error!(ErrorKind::AcmeInvalidChallenge(other.to_string()))),
+        };
+        try!(toml.parse_into("acme.dns_01_hook", &mut cfg.dns_01_hook));
+        Ok(cfg)
+    }
+}
+
+/// Loads `filepath` as the initial `AcmeConfig` and spawns a watcher thread so edits to a
+/// package's `acme.*` settings take effect without restarting the service. Used by
+/// `AcmeClient::watching`, which folds the returned receiver into its renewal loop.
+pub fn watch_config(filepath: PathBuf,
+                     poll_interval: Duration)
+                     -> BldrResult<(Arc<RwLock<AcmeConfig>>,
+                                    mpsc::Receiver<ConfigChangeEvent>,
+                                    thread::JoinHandle<()>)> {
+    use std::io::Read;
+    let mut raw = String::new();
+    try!(try!(File::open(&filepath).map_err(|e| bldr_error!(ErrorKind::IO(e))))
+        .read_to_string(&mut raw)
+        .map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    let initial_toml: toml::Value = try!(raw.parse()
+        .map_err(|_| bldr_error!(ErrorKind::AcmeProtocol(format!("{} is not valid TOML", filepath.display())))));
+    let config = try!(AcmeConfig::from_toml(initial_toml.clone()));
+    let shared = Arc::new(RwLock::new(config));
+    let (tx, rx) = mpsc::channel();
+    let handle = AcmeConfig::watch(shared.clone(), filepath, initial_toml, tx, poll_interval);
+    Ok((shared, rx, handle))
+}
+
+#[derive(Debug, Clone)]
+pub struct CertBundle {
+    pub key_path: PathBuf,
+    pub chain_path: PathBuf,
+    pub not_after: SystemTime,
+}
+
+/// Exposes `bundle`'s paths to the `Reconfigure` hook's template as `{{acme.key_path}}`/
+/// `{{acme.chain_path}}`, so a package's hooks can pick up a renewed cert without
+/// hardcoding the fixed `acme-key.pem`/`acme-fullchain.pem` filenames `download_and_persist`
+/// happens to use today.
+fn cert_bundle_overrides(bundle: &CertBundle) -> BTreeMap<String, toml::Value> {
+    let mut acme = BTreeMap::new();
+    acme.insert("key_path".to_string(), toml::Value::String(bundle.key_path.display().to_string()));
+    acme.insert("chain_path".to_string(), toml::Value::String(bundle.chain_path.display().to_string()));
+    let mut overrides = BTreeMap::new();
+    overrides.insert("acme".to_string(), toml::Value::Table(acme));
+    overrides
+}
+
+/// Drives the ACME v2 protocol for a single package: account registration, order
+/// submission, challenge fulfillment, and certificate issuance/renewal. Holds `package` as
+/// an `Arc` so `schedule_renewal` can move the whole client into a detached thread.
+pub struct AcmeClient {
+    package: Arc<Package>,
+    config: AcmeConfig,
+    client: Client,
+    /// The account's private key, PEM-encoded rather than held as a live `PKey`: `PKey`
+    /// wraps a raw OpenSSL pointer and isn't `Send`, and `schedule_renewal` moves the whole
+    /// `AcmeClient` into a spawned thread. `account_key()` re-parses it on demand.
+    account_key_pem: Vec<u8>,
+    /// Set once registration succeeds; the CA's URL for this account ("kid" per RFC 8555).
+    account_url: Option<String>,
+    /// Set by `watching`: the live config `watch_config`'s background thread keeps
+    /// up to date, and the channel it signals a change on. `schedule_renewal` polls both
+    /// so an edit to `acme.domains`/`acme.challenge` triggers an immediate re-issue instead
+    /// of waiting out the renewal timer.
+    watch: Option<(Arc<RwLock<AcmeConfig>>, mpsc::Receiver<ConfigChangeEvent>)>,
+}
+
+impl AcmeClient {
+    pub fn new(package: Arc<Package>, config: AcmeConfig) -> BldrResult<Self> {
+        let account_key_pem = try!(load_or_generate_account_key_pem(&package));
+        Ok(AcmeClient {
+            package: package,
+            config: config,
+            client: Client::new(),
+            account_key_pem: account_key_pem,
+            account_url: None,
+            watch: None,
+        })
+    }
+
+    /// Parses the stored PEM back into a live `PKey` for signing. Done on demand, rather
+    /// than once and cached on `self`, so the struct itself stays free of non-`Send` types.
+    fn account_key(&self) -> BldrResult<PKey> {
+        PKey::private_key_from_pem(&self.account_key_pem)
+            .map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string())))
+    }
+
+    /// Like `new`, but also watches `config_path` via `watch_config` so edits to the
+    /// package's `acme.*` settings reach `schedule_renewal` without a supervisor restart.
+    pub fn watching(package: Arc<Package>,
+                     config_path: PathBuf,
+                     poll_interval: Duration)
+                     -> BldrResult<Self> {
+        let (shared, rx, _handle) = try!(watch_config(config_path, poll_interval));
+        let config = try!(shared.read()
+            .map(|guard| guard.clone())
+            .map_err(|_| bldr_error!(ErrorKind::AcmeProtocol(String::from("acme config lock poisoned")))));
+        let mut client = try!(AcmeClient::new(package, config));
+        client.watch = Some((shared, rx));
+        Ok(client)
+    }
+
+    pub fn issue(&mut self) -> BldrResult<CertBundle> {
+        try!(self.register_account());
+        let order_url = try!(self.submit_order());
+        let authz_urls = try!(self.fetch_order_authorizations(&order_url));
+        for authz_url in &authz_urls {
+            try!(self.satisfy_authorization(authz_url));
+        }
+        let service_key = try!(generate_service_key());
+        let csr = try!(build_csr(&service_key, &self.config.domains));
+        let cert_url = try!(self.finalize_order(&order_url, &csr));
+        self.download_and_persist(&cert_url, &service_key)
+    }
+
+    /// Sleeps until the cert is within `RENEWAL_WINDOW_DAYS` of expiring, then re-issues it
+    /// and re-runs the `Reconfigure` hook so the new cert takes effect. If constructed via
+    /// `watching`, a config change arriving mid-sleep (e.g. `acme.domains` growing a host)
+    /// cuts the sleep short and re-issues against the refreshed config right away.
+    pub fn schedule_renewal(mut self, reconfigure_hook: Option<Hook>, service_config: Arc<ServiceConfig>) {
+        thread::spawn(move || {
+            loop {
+                let sleep_for = renewal_delay(&self.current_not_after());
+                if self.wait_or_pick_up_config_change(sleep_for) {
+                    outputln!("acme.* config changed, re-issuing certificate ahead of schedule");
+                }
+                match self.issue() {
+                    Ok(bundle) => {
+                        outputln!("ACME renewal succeeded, cert valid until {:?}", bundle.not_after);
+                        if let Some(ref hook) = reconfigure_hook {
+                            let overrides = cert_bundle_overrides(&bundle);
+                            if let Err(e) = hook.compile_with(Some(&service_config), &overrides) {
+                                outputln!("Failed to recompile after cert renewal: {}", e);
+                                continue;
+                            }
+                            if let Err(e) = hook.run_with(Some(&service_config), &overrides) {
+                                outputln!("Reconfigure hook failed after cert renewal: {}", e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        outputln!("ACME renewal failed, will retry: {}", e);
+                        if self.wait_or_pick_up_config_change(Duration::from_secs(RENEWAL_RETRY_DELAY_SECS)) {
+                            outputln!("acme.* config changed, retrying renewal ahead of schedule");
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Sleeps for `duration` unless `watch` fires first, in which case `self.config` is
+    /// refreshed from the watcher's shared value and this returns early. Returns whether a
+    /// config change cut the sleep short; with no watcher (plain `new`), always sleeps the
+    /// full duration and returns `false`.
+    fn wait_or_pick_up_config_change(&mut self, duration: Duration) -> bool {
+        match self.watch {
+            Some((ref shared, ref rx)) => {
+                let waited_from = Instant::now();
+                match rx.recv_timeout(duration) {
+                    Ok(_) => {
+                        if let Ok(guard) = shared.read() {
+                            self.config = guard.clone();
+                        }
+                        true
+                    }
+                    Err(RecvTimeoutError::Timeout) => false,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        // The watcher thread exited (e.g. a poisoned config lock) and
+                        // dropped its sender, so recv_timeout returned immediately instead
+                        // of after `duration`. Sleep out the remainder ourselves so a
+                        // disconnected watcher doesn't turn schedule_renewal's loop into a
+                        // zero-delay hot loop against the CA.
+                        let elapsed = waited_from.elapsed();
+                        if elapsed < duration {
+                            thread::sleep(duration - elapsed);
+                        }
+                        false
+                    }
+                }
+            }
+            None => {
+                thread::sleep(duration);
+                false
+            }
+        }
+    }
+
+    fn current_not_after(&self) -> SystemTime {
+        // Re-computed from the persisted cert rather than cached, so a supervisor restart
+        // picks up the right renewal deadline without re-issuing immediately.
+        cert_not_after(&self.cert_chain_path()).unwrap_or(UNIX_EPOCH)
+    }
+
+    fn cert_chain_path(&self) -> PathBuf {
+        self.package.svc_path().join("files").join("acme-fullchain.pem")
+    }
+
+    fn register_account(&mut self) -> BldrResult<()> {
+        let directory = try!(self.fetch_directory());
+        let payload = json_object(vec![
+            ("termsOfServiceAgreed", Json::Boolean(true)),
+            ("contact", Json::Array(vec![Json::String(format!("mailto:{}", self.config.contact_email))])),
+        ]);
+        let body = try!(self.signed_jws(&directory.new_account_url,
+                                         &directory.new_nonce_url,
+                                         Some(&payload),
+                                         None));
+        let account_url = try!(post_for_location(&self.client, &directory.new_account_url, &body));
+        self.account_url = Some(account_url);
+        Ok(())
+    }
+
+    fn fetch_directory(&self) -> BldrResult<AcmeDirectory> {
+        get_json(&self.client, &self.config.directory_url).and_then(AcmeDirectory::from_json)
+    }
+
+    fn submit_order(&mut self) -> BldrResult<String> {
+        let directory = try!(self.fetch_directory());
+        let identifiers: Vec<Json> = self.config
+            .domains
+            .iter()
+            .map(|d| json_object(vec![("type", Json::String("dns".into())), ("value", Json::String(d.clone()))]))
+            .collect();
+        let payload = json_object(vec![("identifiers", Json::Array(identifiers))]);
+        let kid = self.account_url.clone();
+        let body = try!(self.signed_jws(&directory.new_order_url, &directory.new_nonce_url, Some(&payload), kid));
+        post_for_location(&self.client, &directory.new_order_url, &body)
+    }
+
+    fn fetch_order_authorizations(&self, order_url: &str) -> BldrResult<Vec<String>> {
+        let order = try!(self.post_as_get_json(order_url));
+        match order.find("authorizations").and_then(|a| a.as_array()) {
+            Some(urls) => Ok(urls.iter().filter_map(|u| u.as_string().map(|s| s.to_string())).collect()),
+            None => Err(bldr_error!(ErrorKind::AcmeProtocol(String::from("order missing authorizations")))),
+        }
+    }
+
+    fn satisfy_authorization(&mut self, authz_url: &str) -> BldrResult<()> {
+        let authz = try!(self.post_as_get_json(authz_url));
+        let domain = authz.find_path(&["identifier", "value"])
+            .and_then(|v| v.as_string())
+            .unwrap_or("")
+            .to_string();
+        let challenges = try!(authz.find("challenges")
+            .and_then(|c| c.as_array())
+            .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("authorization missing challenges")))));
+        let wanted = match self.config.challenge {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::Dns01 => "dns-01",
+        };
+        let challenge = try!(challenges.iter()
+            .find(|c| c.find("type").and_then(|t| t.as_string()) == Some(wanted))
+            .ok_or(bldr_error!(ErrorKind::AcmeProtocol(format!("CA offered no {} challenge", wanted)))));
+        let token = try!(challenge.find("token")
+                .and_then(|t| t.as_string())
+                .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("challenge missing token")))))
+            .to_string();
+        let challenge_url = try!(challenge.find("url")
+                .and_then(|u| u.as_string())
+                .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("challenge missing url")))))
+            .to_string();
+        let key_authz = try!(self.key_authorization(&token));
+
+        match self.config.challenge {
+            ChallengeType::Http01 => try!(publish_http_01(&self.package, &token, &key_authz)),
+            ChallengeType::Dns01 => try!(run_dns_01_hook(&self.config, &domain, &key_authz)),
+        }
+
+        let directory = try!(self.fetch_directory());
+        let kid = self.account_url.clone();
+        let body = try!(self.signed_jws(&challenge_url, &directory.new_nonce_url, Some(&json_object(vec![])), kid));
+        try!(post_checked(&self.client, &challenge_url, &body));
+        self.poll_until_valid(authz_url)
+    }
+
+    fn poll_until_valid(&self, authz_url: &str) -> BldrResult<()> {
+        for _ in 0..20 {
+            let authz = try!(self.post_as_get_json(authz_url));
+            match authz.find("status").and_then(|s| s.as_string()) {
+                Some("valid") => return Ok(()),
+                Some("invalid") => {
+                    return Err(bldr_error!(ErrorKind::AcmeProtocol(format!("authorization {} went invalid", authz_url))))
+                }
+                _ => thread::sleep(Duration::from_secs(3)),
+            }
+        }
+        Err(bldr_error!(ErrorKind::AcmeProtocol(format!("authorization {} never became valid", authz_url))))
+    }
+
+    fn finalize_order(&mut self, order_url: &str, csr: &X509Req) -> BldrResult<String> {
+        let order = try!(self.post_as_get_json(order_url));
+        let finalize_url = try!(order.find("finalize")
+                .and_then(|f| f.as_string())
+                .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("order missing finalize url")))))
+            .to_string();
+        let csr_der = try!(csr.to_der().map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string()))));
+        let payload = json_object(vec![("csr", Json::String(base64url(&csr_der)))]);
+        let directory = try!(self.fetch_directory());
+        let kid = self.account_url.clone();
+        let body = try!(self.signed_jws(&finalize_url, &directory.new_nonce_url, Some(&payload), kid));
+        try!(post_for_location(&self.client, &finalize_url, &body));
+
+        for _ in 0..20 {
+            let order = try!(self.post_as_get_json(order_url));
+            match order.find("status").and_then(|s| s.as_string()) {
+                Some("valid") => {
+                    return order.find("certificate")
+                        .and_then(|c| c.as_string())
+                        .map(|s| s.to_string())
+                        .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("order valid but no certificate url"))))
+                }
+                Some("invalid") => {
+                    return Err(bldr_error!(ErrorKind::AcmeProtocol(format!("order {} went invalid", order_url))))
+                }
+                _ => thread::sleep(Duration::from_secs(3)),
+            }
+        }
+        Err(bldr_error!(ErrorKind::AcmeProtocol(format!("order {} never finalized", order_url))))
+    }
+
+    fn download_and_persist(&self, cert_url: &str, service_key: &PKey) -> BldrResult<CertBundle> {
+        let chain_pem = try!(self.post_as_get(cert_url));
+        let files_dir = self.package.svc_path().join("files");
+        try!(fs::create_dir_all(&files_dir).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+        let key_path = files_dir.join("acme-key.pem");
+        let chain_path = files_dir.join("acme-fullchain.pem");
+        let key_pem = try!(service_key.private_key_to_pem().map_err(|e| bldr_error!(ErrorKind::IO(e))));
+        try!(write_key_file(&key_path, &key_pem));
+        try!(write_file(&chain_path, chain_pem.as_bytes()));
+        Ok(CertBundle {
+            key_path: key_path,
+            not_after: cert_not_after(&chain_path).unwrap_or(UNIX_EPOCH),
+            chain_path: chain_path,
+        })
+    }
+
+    fn key_authorization(&self, token: &str) -> BldrResult<String> {
+        let thumbprint = try!(jwk_thumbprint(&try!(self.account_key())));
+        Ok(format!("{}.{}", token, thumbprint))
+    }
+
+    fn signed_jws(&self,
+                  url: &str,
+                  new_nonce_url: &str,
+                  payload: Option<&Json>,
+                  kid: Option<String>)
+                  -> BldrResult<String> {
+        let nonce = try!(fetch_nonce(&self.client, new_nonce_url));
+        sign_jws(&try!(self.account_key()), url, &nonce, payload, kid)
+    }
+
+    /// RFC 8555 §6.2: every resource but the directory and `newNonce` must be fetched with
+    /// POST-as-GET — a signed JWS whose payload is the empty string — rather than a plain
+    /// GET. A spec-compliant CA 405s a bare GET on orders, authorizations, and certificates.
+    fn post_as_get(&self, url: &str) -> BldrResult<String> {
+        let directory = try!(self.fetch_directory());
+        let kid = self.account_url.clone();
+        let body = try!(self.signed_jws(url, &directory.new_nonce_url, None, kid));
+        post_for_body(&self.client, url, &body)
+    }
+
+    fn post_as_get_json(&self, url: &str) -> BldrResult<Json> {
+        let body = try!(self.post_as_get(url));
+        Json::from_str(&body).map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string())))
+    }
+}
+
+struct AcmeDirectory {
+    new_account_url: String,
+    new_order_url: String,
+    new_nonce_url: String,
+}
+
+impl AcmeDirectory {
+    fn from_json(json: Json) -> BldrResult<Self> {
+        let new_account_url = try!(json.find("newAccount")
+                .and_then(|v| v.as_string())
+                .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("directory missing newAccount")))))
+            .to_string();
+        let new_order_url = try!(json.find("newOrder")
+                .and_then(|v| v.as_string())
+                .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("directory missing newOrder")))))
+            .to_string();
+        let new_nonce_url = try!(json.find("newNonce")
+                .and_then(|v| v.as_string())
+                .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("directory missing newNonce")))))
+            .to_string();
+        Ok(AcmeDirectory {
+            new_account_url: new_account_url,
+            new_order_url: new_order_url,
+            new_nonce_url: new_nonce_url,
+        })
+    }
+}
+
+/// Returns the account key's PEM bytes rather than a parsed `PKey` so callers can hold it
+/// across a thread boundary (see `AcmeClient::account_key_pem`).
+fn load_or_generate_account_key_pem(package: &Package) -> BldrResult<Vec<u8>> {
+    let path = package.svc_path().join("files").join("acme-account-key.pem");
+    if path.exists() {
+        fs::metadata(&path).map(|_| ()).and_then(|_| fs::read(&path).map_err(From::from))
+            .map_err(|e: ::std::io::Error| bldr_error!(ErrorKind::IO(e)))
+    } else {
+        let key = try!(generate_service_key());
+        let key_pem = try!(key.private_key_to_pem().map_err(|e| bldr_error!(ErrorKind::IO(e))));
+        try!(write_key_file(&path, &key_pem));
+        Ok(key_pem)
+    }
+}
+
+fn generate_service_key() -> BldrResult<PKey> {
+    let mut key = PKey::new();
+    key.gen(2048);
+    Ok(key)
+}
+
+/// Builds a CSR naming every entry in `domains` as a subjectAltName, since `finalize_order`
+/// sends this same CSR for the whole order and a CA rejects one that doesn't cover every
+/// identifier the order authorized.
+fn build_csr(key: &PKey, domains: &[String]) -> BldrResult<X509Req> {
+    let primary = try!(domains.first()
+        .ok_or(bldr_error!(ErrorKind::AcmeProtocol(String::from("acme.domains is empty")))));
+    let alt_names = domains.iter().map(|d| format!("DNS:{}", d)).collect();
+    let generator = X509Generator::new()
+        .set_sign_hash(HashType::SHA256)
+        .add_name("CN".to_string(), primary.clone())
+        .add_extension(Extension::SubjectAltName(alt_names));
+    generator.request(key).map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string())))
+}
+
+fn publish_http_01(package: &Package, token: &str, key_authorization: &str) -> BldrResult<()> {
+    let dir = package.svc_path().join("files").join(".well-known").join("acme-challenge");
+    try!(fs::create_dir_all(&dir).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    write_file(&dir.join(token), key_authorization.as_bytes())
+}
+
+fn run_dns_01_hook(config: &AcmeConfig, domain: &str, key_authorization: &str) -> BldrResult<()> {
+    let hook = match config.dns_01_hook {
+        Some(ref path) => path,
+        None => return Err(bldr_error!(ErrorKind::AcmeProtocol(String::from("dns-01 challenge configured without acme.dns_01_hook")))),
+    };
+    let status = try!(Command::new(hook)
+        .arg(domain)
+        .arg(key_authorization)
+        .status()
+        .map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    if status.success() {
+        Ok(())
+    } else {
+        Err(bldr_error!(ErrorKind::AcmeProtocol(format!("dns_01_hook exited {:?}", status.code()))))
+    }
+}
+
+fn renewal_delay(not_after: &SystemTime) -> Duration {
+    let renew_at = *not_after - Duration::from_secs(RENEWAL_WINDOW_DAYS * 24 * 60 * 60);
+    renew_at.duration_since(SystemTime::now()).unwrap_or(Duration::from_secs(0))
+}
+
+/// Parses the leaf certificate's real `notAfter` out of a PEM chain; the file's mtime is
+/// ~now right after `download_and_persist` and would make every deadline look past-due.
+fn cert_not_after(chain_path: &Path) -> Option<SystemTime> {
+    let pem = match fs::read(chain_path) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+    let cert = match X509::from_pem(&pem) {
+        Ok(c) => c,
+        Err(_) => return None,
+    };
+    parse_asn1_time(&cert.not_after().to_string())
+}
+
+/// Parses the format OpenSSL's `Display` impl renders `notAfter` as, e.g.
+/// "Jan  1 00:00:00 2030 GMT".
+fn parse_asn1_time(s: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    if fields.len() != 5 || fields[4] != "GMT" {
+        return None;
+    }
+    let month = match fields[0] {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let day: u32 = match fields[1].parse() {
+        Ok(d) => d,
+        Err(_) => return None,
+    };
+    let time_parts: Vec<&str> = fields[2].split(':').collect();
+    if time_parts.len() != 3 {
+        return None;
+    }
+    let hour: i64 = match time_parts[0].parse() {
+        Ok(h) => h,
+        Err(_) => return None,
+    };
+    let min: i64 = match time_parts[1].parse() {
+        Ok(m) => m,
+        Err(_) => return None,
+    };
+    let sec: i64 = match time_parts[2].parse() {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+    let year: i64 = match fields[3].parse() {
+        Ok(y) => y,
+        Err(_) => return None,
+    };
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86_400 + hour * 3_600 + min * 60 + sec;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Howard Hinnant's days-from-civil algorithm (proleptic Gregorian calendar, days relative
+/// to the Unix epoch).
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400; // [0, 399]
+    let mp = (m as i64 + 9) % 12; // [0, 11]
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1; // [0, 365]
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy; // [0, 146096]
+    era * 146_097 + doe - 719_468
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> BldrResult<()> {
+    let mut file = try!(File::create(path).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    try!(file.write_all(contents).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    Ok(())
+}
+
+/// Like `write_file`, but for private key material: creates the file `0600` up front
+/// instead of relying on the process umask, so the account/service keys aren't left
+/// group/world-readable.
+fn write_key_file(path: &Path, contents: &[u8]) -> BldrResult<()> {
+    let mut file = try!(OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+        .map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    try!(file.write_all(contents).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    Ok(())
+}
+
+fn json_object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    bytes.to_base64(base64::Config {
+        char_set: base64::CharacterSet::UrlSafe,
+        newline: base64::Newline::LF,
+        pad: false,
+        line_length: None,
+    })
+}
+
+fn jwk_thumbprint(key: &PKey) -> BldrResult<String> {
+    // RFC 7638: the SHA-256 digest of the JWK's required members, in lexicographic order,
+    // with no insignificant whitespace.
+    let jwk = jwk_for(key);
+    let digest = ::openssl::crypto::hash::hash(HashType::SHA256, jwk.to_string().as_bytes());
+    Ok(base64url(&digest))
+}
+
+fn jwk_for(key: &PKey) -> Json {
+    // RSA-only for now, matching `generate_service_key`/`load_or_generate_account_key_pem`.
+    let (n, e) = key.public_key_to_rsa_components();
+    json_object(vec![
+        ("e", Json::String(base64url(&e))),
+        ("kty", Json::String("RSA".into())),
+        ("n", Json::String(base64url(&n))),
+    ])
+}
+
+/// `payload` is `None` for a POST-as-GET request, whose JWS payload per RFC 8555 §6.2 is
+/// the empty string, not the empty JSON object.
+fn sign_jws(key: &PKey,
+            url: &str,
+            nonce: &str,
+            payload: Option<&Json>,
+            kid: Option<String>)
+            -> BldrResult<String> {
+    let protected = match kid {
+        Some(kid) => json_object(vec![
+            ("alg", Json::String("RS256".into())),
+            ("kid", Json::String(kid)),
+            ("url", Json::String(url.to_string())),
+            ("nonce", Json::String(nonce.to_string())),
+        ]),
+        None => json_object(vec![
+            ("alg", Json::String("RS256".into())),
+            ("jwk", jwk_for(key)),
+            ("url", Json::String(url.to_string())),
+            ("nonce", Json::String(nonce.to_string())),
+        ]),
+    };
+    let protected_b64 = base64url(protected.to_string().as_bytes());
+    let payload_b64 = match payload {
+        Some(payload) => base64url(payload.to_string().as_bytes()),
+        None => String::new(),
+    };
+    let signing_input = format!("{}.{}", protected_b64, payload_b64);
+    let signature = key.sign_with_hash(signing_input.as_bytes(), HashType::SHA256);
+    Ok(json_object(vec![
+        ("protected", Json::String(protected_b64)),
+        ("payload", Json::String(payload_b64)),
+        ("signature", Json::String(base64url(&signature))),
+    ]).to_string())
+}
+
+/// `HEAD`s the directory's `newNonce` endpoint for a fresh nonce; reusing one gets the
+/// request rejected with `badNonce`.
+fn fetch_nonce(client: &Client, new_nonce_url: &str) -> BldrResult<String> {
+    let res = try!(client.head(new_nonce_url)
+        .send()
+        .map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string()))));
+    res.headers
+        .get_raw("Replay-Nonce")
+        .and_then(|v| v.first())
+        .and_then(|v| String::from_utf8(v.clone()).ok())
+        .ok_or(bldr_error!(ErrorKind::AcmeProtocol(format!("{} response missing Replay-Nonce header",
+                                                           new_nonce_url))))
+}
+
+fn get_json(client: &Client, url: &str) -> BldrResult<Json> {
+    let body = try!(get_raw(client, url));
+    Json::from_str(&body).map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string())))
+}
+
+fn get_raw(client: &Client, url: &str) -> BldrResult<String> {
+    use std::io::Read;
+    let mut res = try!(client.get(url).send().map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string()))));
+    let mut body = String::new();
+    try!(res.read_to_string(&mut body).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    Ok(body)
+}
+
+fn post_for_location(client: &Client, url: &str, body: &str) -> BldrResult<String> {
+    let res = try!(client.post(url)
+        .header(ContentType("application/jose+json".parse().unwrap()))
+        .body(body)
+        .send()
+        .map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string()))));
+    if !res.status.is_success() {
+        return Err(bldr_error!(ErrorKind::AcmeProtocol(format!("{} returned {}", url, res.status))));
+    }
+    res.headers
+        .get_raw("Location")
+        .and_then(|v| v.first())
+        .and_then(|v| String::from_utf8(v.clone()).ok())
+        .ok_or(bldr_error!(ErrorKind::AcmeProtocol(format!("{} response missing Location header", url))))
+}
+
+/// POSTs a signed POST-as-GET body and returns the response body, the way a plain GET
+/// would for a resource RFC 8555 requires to be fetched via POST-as-GET instead.
+fn post_for_body(client: &Client, url: &str, body: &str) -> BldrResult<String> {
+    use std::io::Read;
+    let mut res = try!(client.post(url)
+        .header(ContentType("application/jose+json".parse().unwrap()))
+        .body(body)
+        .send()
+        .map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string()))));
+    if !res.status.is_success() {
+        return Err(bldr_error!(ErrorKind::AcmeProtocol(format!("{} returned {}", url, res.status))));
+    }
+    let mut text = String::new();
+    try!(res.read_to_string(&mut text).map_err(|e| bldr_error!(ErrorKind::IO(e))));
+    Ok(text)
+}
+
+/// Like `post_for_location`, but doesn't require a `Location` header in the response —
+/// RFC 8555 only mandates one on a handful of endpoints, and a challenge-readiness POST
+/// isn't one of them.
+fn post_checked(client: &Client, url: &str, body: &str) -> BldrResult<()> {
+    let res = try!(client.post(url)
+        .header(ContentType("application/jose+json".parse().unwrap()))
+        .body(body)
+        .send()
+        .map_err(|e| bldr_error!(ErrorKind::AcmeProtocol(e.to_string()))));
+    if res.status.is_success() {
+        Ok(())
+    } else {
+        Err(bldr_error!(ErrorKind::AcmeProtocol(format!("{} returned {}", url, res.status))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use core::config::{ConfigFile, toml_fixture as toml};
+    use error::ErrorKind;
+
+    use super::{AcmeConfig, ChallengeType, days_from_civil, parse_asn1_time};
+
+    #[test]
+    fn acme_config_from_toml_parses_every_key() {
+        let cfg = AcmeConfig::from_toml(toml(r#"
+            [acme]
+            ca_url = "https://example.test/directory"
+            contact_email = "ops@example.test"
+            domains = ["example.test", "www.example.test"]
+            challenge = "dns-01"
+            dns_01_hook = "/bin/dns-hook"
+        "#)).unwrap();
+        assert_eq!("https://example.test/directory", cfg.directory_url);
+        assert_eq!("ops@example.test", cfg.contact_email);
+        assert_eq!(vec!["example.test".to_string(), "www.example.test".to_string()], cfg.domains);
+        assert_eq!(ChallengeType::Dns01, cfg.challenge);
+        assert_eq!(Some("/bin/dns-hook".to_string()), cfg.dns_01_hook);
+    }
+
+    #[test]
+    fn acme_config_from_toml_defaults_challenge_to_http01() {
+        let cfg = AcmeConfig::from_toml(toml("[acme]\ndomains = [\"example.test\"]")).unwrap();
+        assert_eq!(ChallengeType::Http01, cfg.challenge);
+    }
+
+    #[test]
+    fn acme_config_from_toml_rejects_unknown_challenge() {
+        match AcmeConfig::from_toml(toml("[acme]\nchallenge = \"tls-alpn-01\"")) {
+            Err(e) => {
+                match e.err {
+                    ErrorKind::AcmeInvalidChallenge(ref got) => assert_eq!("tls-alpn-01", got),
+                    _ => panic!("expected AcmeInvalidChallenge"),
+                }
+            }
+            Ok(_) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn days_from_civil_matches_unix_epoch() {
+        assert_eq!(0, days_from_civil(1970, 1, 1));
+    }
+
+    #[test]
+    fn days_from_civil_handles_leap_years() {
+        assert_eq!(days_from_civil(2000, 3, 1), days_from_civil(2000, 2, 29) + 1);
+    }
+
+    #[test]
+    fn parse_asn1_time_parses_openssl_display_format() {
+        let parsed = parse_asn1_time("Jan  1 00:00:00 1970 GMT").unwrap();
+        assert_eq!(UNIX_EPOCH, parsed);
+    }
+
+    #[test]
+    fn parse_asn1_time_accounts_for_time_of_day() {
+        let parsed = parse_asn1_time("Jan  1 01:02:03 1970 GMT").unwrap();
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(3600 + 2 * 60 + 3), parsed);
+    }
+
+    #[test]
+    fn parse_asn1_time_rejects_non_gmt_and_malformed_input() {
+        assert!(parse_asn1_time("Jan  1 00:00:00 1970 UTC").is_none());
+        assert!(parse_asn1_time("not a time").is_none());
+    }
+}
@@ -0,0 +1,110 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use std::time::Duration;
+
+use core::error::Error as CoreError;
+use package::hooks::HookType;
+
+pub type BldrResult<T> = result::Result<T, BldrError>;
+
+#[derive(Debug)]
+pub struct BldrError {
+    pub err: ErrorKind,
+}
+
+impl BldrError {
+    pub fn new(err: ErrorKind) -> Self {
+        BldrError { err: err }
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    IO(io::Error),
+    /// A hook exited non-zero, or its output pipes could not be set up. Carries the hook
+    /// type, the exit code (`-1` when there is none: the pipes were missing, or the hook was
+    /// killed by a signal), and the hook's captured stdout/stderr. A failure to spawn the
+    /// hook at all is reported as `IO` instead.
+    HookFailed(HookType, i32, String),
+    /// A hook was killed after running past its configured timeout. Carries the hook type
+    /// and the timeout that was exceeded, kept distinct from `HookFailed` so callers like
+    /// `run_health_check` can tell a wedged process from one that exited on its own.
+    HookTimedOut(HookType, Duration),
+    /// The ACME CA's directory, account, order, authorization, or challenge responses
+    /// didn't match the RFC 8555 shape an `AcmeClient` call expected.
+    AcmeProtocol(String),
+    /// `acme.challenge` was set to something other than `http-01` or `dns-01`.
+    AcmeInvalidChallenge(String),
+    /// A `core::config::ConfigFile` load/parse/reload failed, e.g. while watching
+    /// `acme.*` settings for changes.
+    Config(CoreError),
+}
+
+impl fmt::Display for BldrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self.err {
+            ErrorKind::IO(ref e) => format!("{}", e),
+            ErrorKind::HookFailed(ref htype, code, ref output) => {
+                format!("Hook '{}' exited with code {}, output: {}", htype, code, output)
+            }
+            ErrorKind::HookTimedOut(ref htype, duration) => {
+                format!("Hook '{}' timed out after {}s", htype, duration.as_secs())
+            }
+            ErrorKind::AcmeProtocol(ref msg) => format!("ACME protocol error: {}", msg),
+            ErrorKind::AcmeInvalidChallenge(ref given) => {
+                format!("Invalid acme.challenge '{}', expected 'http-01' or 'dns-01'", given)
+            }
+            ErrorKind::Config(ref e) => format!("{}", e),
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl error::Error for BldrError {
+    fn description(&self) -> &str {
+        match self.err {
+            ErrorKind::IO(ref e) => e.description(),
+            ErrorKind::HookFailed(..) => "hook execution failed",
+            ErrorKind::HookTimedOut(..) => "hook execution timed out",
+            ErrorKind::AcmeProtocol(..) => "ACME protocol error",
+            ErrorKind::AcmeInvalidChallenge(..) => "invalid acme.challenge value",
+            ErrorKind::Config(..) => "config load/parse/reload error",
+        }
+    }
+}
+
+impl From<io::Error> for BldrError {
+    fn from(err: io::Error) -> Self {
+        BldrError::new(ErrorKind::IO(err))
+    }
+}
+
+impl From<CoreError> for BldrError {
+    fn from(err: CoreError) -> Self {
+        BldrError::new(ErrorKind::Config(err))
+    }
+}
+
+#[macro_export]
+macro_rules! bldr_error {
+    ($e:expr) => (::error::BldrError::new($e))
+}
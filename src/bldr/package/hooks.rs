@@ -15,16 +15,24 @@
 // limitations under the License.
 //
 
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt;
 use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use libc;
 use mustache;
+use toml;
 
-use error::{BldrResult, ErrorKind};
+use core::config::ParseInto;
+use error::{BldrError, BldrResult, ErrorKind};
 use package::Package;
 use service_config::ServiceConfig;
 use util::convert;
@@ -50,71 +58,188 @@ impl fmt::Display for HookType {
     }
 }
 
+/// The result of running a `health_check` hook, following the widely-used convention of
+/// mapping a process's exit code onto a small set of severities rather than a bare
+/// success/failure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthCheckStatus {
+    /// Exit code 0.
+    Ok,
+    /// Exit code 1.
+    Warning,
+    /// Any other exit code.
+    Critical,
+    /// The hook could not be run at all, or exited in a way that doesn't map to an exit
+    /// code (e.g. killed by a signal).
+    Unknown,
+}
+
+impl fmt::Display for HealthCheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &HealthCheckStatus::Ok => write!(f, "ok"),
+            &HealthCheckStatus::Warning => write!(f, "warning"),
+            &HealthCheckStatus::Critical => write!(f, "critical"),
+            &HealthCheckStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// How long an `init` or `health_check` hook may run before it's considered wedged and
+/// killed, absent a more specific `hooks.<type>.timeout` in the package's config.
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a timed-out hook is given to exit cleanly after SIGTERM before SIGKILL follows.
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(5);
+/// How many trailing lines of a hook's combined stdout/stderr `run_with` keeps for its
+/// return value and for `HookFailed`/`HookTimedOut`. A chatty wedged hook can log for the
+/// whole timeout before being killed, so this is capped rather than accumulated without
+/// limit.
+const CAPTURED_OUTPUT_TAIL_LINES: usize = 100;
+
+/// Maps a `HookFailed` exit code to the `HealthCheckStatus` a health check's caller should
+/// report. `-1` means there was no exit code (the hook's pipes could not be set up, or it
+/// was killed by a signal).
+fn health_check_status_for_exit_code(code: i32) -> HealthCheckStatus {
+    match code {
+        1 => HealthCheckStatus::Warning,
+        -1 => HealthCheckStatus::Unknown,
+        _ => HealthCheckStatus::Critical,
+    }
+}
+
+#[derive(Clone)]
 pub struct Hook {
     pub htype: HookType,
     pub template: PathBuf,
     pub path: PathBuf,
+    /// `None` means the hook is allowed to run indefinitely. `run` is the long-lived
+    /// foreground process that *is* the supervised service, so it (and `reconfigure`,
+    /// which can itself legitimately block on the service for a while) default to no
+    /// deadline; only the short-lived `init`/`health_check` hooks default to one.
+    pub timeout: Option<Duration>,
 }
 
 impl Hook {
     pub fn new(htype: HookType, template: PathBuf, path: PathBuf) -> Self {
+        let timeout = match htype {
+            HookType::Run | HookType::Reconfigure => None,
+            HookType::Init | HookType::HealthCheck => Some(DEFAULT_HOOK_TIMEOUT),
+        };
         Hook {
             htype: htype,
             template: template,
             path: path,
+            timeout: timeout,
         }
     }
 
     pub fn run(&self, context: Option<&ServiceConfig>) -> BldrResult<String> {
-        try!(self.compile(context));
+        self.run_with(context, &BTreeMap::new())
+    }
+
+    /// Like `run`, but passes `overrides` through to `compile_with` rather than `compile`.
+    pub fn run_with(&self,
+                     context: Option<&ServiceConfig>,
+                     overrides: &BTreeMap<String, toml::Value>)
+                     -> BldrResult<String> {
+        try!(self.compile_with(context, overrides));
         let mut child = try!(Command::new(&self.path)
                                  .stdin(Stdio::null())
                                  .stdout(Stdio::piped())
                                  .stderr(Stdio::piped())
                                  .spawn());
-        {
-            let mut c_stdout = match child.stdout {
-                Some(ref mut s) => s,
-                None => return Err(bldr_error!(ErrorKind::HookFailed(self.htype.clone(),
-                                                                     -1,
-                                                                     String::from("Failed")))),
-            };
-            let mut line = output_format!(P: "hook", "{}", &self.htype);
-            loop {
-                let mut buf = [0u8; 1]; // Our byte buffer
-                let len = try!(c_stdout.read(&mut buf));
-                match len {
-                    0 => {
-                        // 0 == EOF, so stop writing and finish progress
-                        break;
+        let stdout = match child.stdout.take() {
+            Some(s) => s,
+            None => return Err(bldr_error!(ErrorKind::HookFailed(self.htype.clone(),
+                                                                 -1,
+                                                                 String::from("Failed")))),
+        };
+        let stderr = match child.stderr.take() {
+            Some(s) => s,
+            None => return Err(bldr_error!(ErrorKind::HookFailed(self.htype.clone(),
+                                                                 -1,
+                                                                 String::from("Failed")))),
+        };
+
+        // Two reader threads feed lines from stdout and stderr into one channel so they can
+        // be interleaved into the `output_format!` prefix as they arrive, instead of the
+        // old one-byte-at-a-time `read()` loop that only ever looked at stdout.
+        let (tx, rx) = mpsc::channel();
+        spawn_line_reader(stdout, tx.clone());
+        spawn_line_reader(stderr, tx);
+
+        let mut captured: VecDeque<String> = VecDeque::new();
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let mut open_readers = 2;
+        while open_readers > 0 {
+            let received = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining == Duration::new(0, 0) {
+                        return Err(self.timeout_error(&mut child));
                     }
-                    _ => {
-                        // Write the buffer to the BufWriter on the Heap
-                        let buf_string = String::from_utf8_lossy(&buf[0..len]);
-                        line.push_str(&buf_string);
-                        if line.contains("\n") {
-                            print!("{}", line);
-                            line = output_format!(P: "hook", "{}", &self.htype);
-                        }
+                    match rx.recv_timeout(remaining) {
+                        Ok(line) => Ok(line),
+                        Err(RecvTimeoutError::Timeout) => return Err(self.timeout_error(&mut child)),
+                        Err(RecvTimeoutError::Disconnected) => Err(()),
+                    }
+                }
+                // No deadline: block indefinitely for the next line, same as before a
+                // timeout existed at all.
+                None => rx.recv().map_err(|_| ()),
+            };
+            match received {
+                Ok(Some(line)) => {
+                    print!("{}{}\n", output_format!(P: "hook", "{}", &self.htype), line);
+                    captured.push_back(line);
+                    if captured.len() > CAPTURED_OUTPUT_TAIL_LINES {
+                        captured.pop_front();
                     }
                 }
+                Ok(None) => open_readers -= 1,
+                Err(()) => break,
             }
         }
+
+        let captured = join_captured_tail(captured);
         let exit_status = try!(child.wait());
         if exit_status.success() {
-            Ok(String::from("Finished"))
+            Ok(captured)
         } else {
             Err(bldr_error!(ErrorKind::HookFailed(self.htype.clone(),
                                                   exit_status.code().unwrap_or(-1),
-                                                  String::from("Failed"))))
+                                                  captured)))
         }
     }
 
+    /// Sends SIGTERM, gives the child `KILL_GRACE_PERIOD` to exit on its own, then SIGKILLs
+    /// it, and builds the `HookTimedOut` this wedged run should be reported as.
+    fn timeout_error(&self, child: &mut Child) -> BldrError {
+        kill_child(child, KILL_GRACE_PERIOD);
+        let timeout = self.timeout.unwrap_or(Duration::new(0, 0));
+        bldr_error!(ErrorKind::HookTimedOut(self.htype.clone(), timeout))
+    }
+
     pub fn compile(&self, context: Option<&ServiceConfig>) -> BldrResult<()> {
+        self.compile_with(context, &BTreeMap::new())
+    }
+
+    /// Like `compile`, but merges `overrides` on top of `context`'s own toml before the
+    /// template is filled in, so data that isn't part of `ServiceConfig` itself (e.g. a
+    /// just-renewed ACME cert's paths) is still visible to the hook's template.
+    pub fn compile_with(&self,
+                         context: Option<&ServiceConfig>,
+                         overrides: &BTreeMap<String, toml::Value>)
+                         -> BldrResult<()> {
         if let Some(ctx) = context {
             let template = try!(mustache::compile_path(&self.template));
             let mut out = Vec::new();
-            let toml = try!(ctx.compile_toml());
+            let mut toml = try!(ctx.compile_toml());
+            if let toml::Value::Table(ref mut table) = toml {
+                for (key, value) in overrides {
+                    merge_toml_value(table, key.clone(), value.clone());
+                }
+            }
             let data = convert::toml_table_to_mustache(toml);
             template.render_data(&mut out, &data);
             let data = try!(String::from_utf8(out));
@@ -134,6 +259,72 @@ impl Hook {
     }
 }
 
+/// Inserts `key: value` into `table`, merging nested tables key-by-key instead of
+/// replacing them outright. Without this, an override like `acme = {key_path = ...}`
+/// would wipe out every other pre-existing `acme.*` setting (e.g. `domains`,
+/// `challenge`) rather than just adding to it.
+fn merge_toml_value(table: &mut BTreeMap<String, toml::Value>, key: String, value: toml::Value) {
+    if let toml::Value::Table(ref incoming) = value {
+        if let Some(&mut toml::Value::Table(ref mut existing)) = table.get_mut(&key) {
+            for (nested_key, nested_value) in incoming.clone() {
+                merge_toml_value(existing, nested_key, nested_value);
+            }
+            return;
+        }
+    }
+    table.insert(key, value);
+}
+
+/// Joins the up-to-`CAPTURED_OUTPUT_TAIL_LINES` lines `run_with` kept into the single
+/// string its callers expect: one line per entry plus a trailing newline, or an empty
+/// string when the hook printed nothing.
+fn join_captured_tail(lines: VecDeque<String>) -> String {
+    if lines.is_empty() {
+        return String::new();
+    }
+    let mut joined = lines.into_iter().collect::<Vec<_>>().join("\n");
+    joined.push('\n');
+    joined
+}
+
+/// Reads `stream` line-by-line on its own thread, forwarding each line to `tx`. Sends
+/// `None` once the stream hits EOF so the caller can tell the two reader threads (stdout
+/// and stderr) apart from a real timeout.
+fn spawn_line_reader<R>(stream: R, tx: mpsc::Sender<Option<String>>)
+    where R: Read + Send + 'static
+{
+    thread::spawn(move || {
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            match line {
+                Ok(l) => {
+                    if tx.send(Some(l)).is_err() {
+                        return;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        let _ = tx.send(None);
+    });
+}
+
+/// SIGTERMs `child`, gives it `grace_period` to exit on its own, then SIGKILLs it.
+fn kill_child(child: &mut Child, grace_period: Duration) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline {
+        match child.try_wait() {
+            Ok(Some(_)) | Err(_) => return,
+            Ok(None) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
 pub struct HookTable<'a> {
     pub package: &'a Package,
     pub init_hook: Option<Hook>,
@@ -153,6 +344,32 @@ impl<'a> HookTable<'a> {
         }
     }
 
+    /// Runs the `health_check` hook, if one is configured, and maps its exit status to a
+    /// `HealthCheckStatus` rather than only distinguishing success from failure. The
+    /// hook's captured stdout/stderr is returned alongside so a supervisor can publish a
+    /// human-readable message over gossip together with the state.
+    pub fn run_health_check(&self, context: Option<&ServiceConfig>) -> (HealthCheckStatus, String) {
+        match self.health_check_hook {
+            Some(ref hook) => {
+                match hook.run(context) {
+                    Ok(output) => (HealthCheckStatus::Ok, output),
+                    Err(e) => {
+                        match e.err {
+                            ErrorKind::HookFailed(_, code, ref output) => {
+                                (health_check_status_for_exit_code(code), output.clone())
+                            }
+                            // A wedged health check is a real failure of the service being
+                            // checked, so it's Critical rather than Unknown.
+                            ErrorKind::HookTimedOut(..) => (HealthCheckStatus::Critical, e.to_string()),
+                            _ => (HealthCheckStatus::Unknown, e.to_string()),
+                        }
+                    }
+                }
+            }
+            None => (HealthCheckStatus::Unknown, String::from("no health_check hook configured")),
+        }
+    }
+
     pub fn load_hooks(&mut self) -> &mut Self {
         let hook_path = self.package.join_path("hooks");
         let path = Path::new(&hook_path);
@@ -178,4 +395,92 @@ impl<'a> HookTable<'a> {
             Err(_) => None,
         }
     }
+
+    /// Overrides each loaded hook's timeout from the package's `hooks.<type>.timeout`
+    /// config keys (in seconds). A hook whose key isn't set keeps the timeout `Hook::new`
+    /// gave it: `DEFAULT_HOOK_TIMEOUT` for `init`/`health_check`, none for
+    /// `run`/`reconfigure`.
+    pub fn configure_timeouts(&mut self, config: &toml::Value) {
+        configure_timeout(&mut self.init_hook, config, "hooks.init.timeout");
+        configure_timeout(&mut self.health_check_hook, config, "hooks.health_check.timeout");
+        configure_timeout(&mut self.reconfigure_hook, config, "hooks.reconfigure.timeout");
+        configure_timeout(&mut self.run_hook, config, "hooks.run.timeout");
+    }
+}
+
+fn configure_timeout(hook: &mut Option<Hook>, config: &toml::Value, field: &'static str) {
+    if let Some(ref mut hook) = *hook {
+        let mut secs = 0u64;
+        if let Ok(true) = config.parse_into(field, &mut secs) {
+            // An explicit config value always wins, including for `run`/`reconfigure`,
+            // whose constructor leaves them with no default deadline.
+            hook.timeout = Some(Duration::from_secs(secs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use toml;
+
+    use core::config::toml_fixture;
+    use error::ErrorKind;
+    use package::hooks::HookType;
+
+    use super::{HealthCheckStatus, health_check_status_for_exit_code, merge_toml_value};
+
+    fn toml_table(raw: &str) -> ::std::collections::BTreeMap<String, toml::Value> {
+        match toml_fixture(raw) {
+            toml::Value::Table(table) => table,
+            other => panic!("expected a table, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn merge_toml_value_merges_nested_tables_instead_of_overwriting() {
+        let mut table = toml_table("[acme]\ndomains = [\"example.test\"]\nchallenge = \"http-01\"");
+        for (key, value) in toml_table("[acme]\nkey_path = \"/svc/files/acme-key.pem\"") {
+            merge_toml_value(&mut table, key, value);
+        }
+        let acme = match table.get("acme") {
+            Some(&toml::Value::Table(ref acme)) => acme,
+            other => panic!("expected acme to still be a table, got {:?}", other),
+        };
+        assert!(acme.contains_key("domains"));
+        assert!(acme.contains_key("challenge"));
+        assert_eq!(Some(&toml::Value::String("/svc/files/acme-key.pem".to_string())),
+                   acme.get("key_path"));
+    }
+
+    #[test]
+    fn merge_toml_value_overwrites_non_table_values() {
+        let mut table = toml_table("foo = 1");
+        merge_toml_value(&mut table, "foo".to_string(), toml::Value::Integer(2));
+        assert_eq!(Some(&toml::Value::Integer(2)), table.get("foo"));
+    }
+
+    #[test]
+    fn health_check_status_maps_code_one_to_warning() {
+        assert_eq!(HealthCheckStatus::Warning, health_check_status_for_exit_code(1));
+    }
+
+    #[test]
+    fn health_check_status_maps_spawn_failure_to_unknown() {
+        assert_eq!(HealthCheckStatus::Unknown, health_check_status_for_exit_code(-1));
+    }
+
+    #[test]
+    fn health_check_status_maps_other_nonzero_codes_to_critical() {
+        assert_eq!(HealthCheckStatus::Critical, health_check_status_for_exit_code(2));
+    }
+
+    #[test]
+    fn hook_timed_out_is_distinct_from_hook_failed() {
+        match ErrorKind::HookTimedOut(HookType::HealthCheck, Duration::from_secs(60)) {
+            ErrorKind::HookTimedOut(HookType::HealthCheck, d) => assert_eq!(60, d.as_secs()),
+            _ => panic!("expected HookTimedOut"),
+        }
+    }
 }
\ No newline at end of file